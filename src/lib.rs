@@ -25,10 +25,12 @@
 
 extern crate snailquote;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::str;
 
 use snailquote::{unescape, escape};
@@ -36,73 +38,438 @@ use snailquote::{unescape, escape};
 
 /// An opened environment file, whose contents are buffered into memory.
 pub struct EnvFile {
-    /// Where the environment file exists in memory.
-    pub path:  PathBuf,
+    /// Where the environment file exists on disk, or `None` if it was parsed from a string,
+    /// a reader, or otherwise without a backing path.
+    pub path: Option<PathBuf>,
     /// The data that was parsed from the file.
     pub store: BTreeMap<String, String>,
+    /// Keys that have been explicitly unset, and should be removed from an inherited environment.
+    pub removed: BTreeSet<String>,
+    /// The original layout of the file, used by `write` to reproduce comments, blank lines,
+    /// and key order instead of serializing `store` alphabetically.
+    lines: Vec<Line>,
 }
 
-fn parse_line(entry: &[u8]) -> Option<(String, String)> {
-    str::from_utf8(entry).ok().and_then(|l| {
-        let line = l.trim();
-        // Ignore comment line
-        if line.starts_with('#') {
-            return None;
-        }
-        let vline = line.as_bytes();
-        vline.iter().position(|&x| x == b'=').and_then(|pos| {
-            str::from_utf8(&vline[..pos]).ok().and_then(|x| {
-                str::from_utf8(&vline[pos+1..]).ok().and_then(|right| {
-                    // The right hand side value can be a quoted string
-                    unescape(right).ok().map(|y| (x.to_owned(), y))
-                })
-            })
-        })
-    })
+/// A single line of the original document, as seen by the order-preserving writer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    /// An empty (or whitespace-only) line.
+    Blank,
+    /// A comment line, or any other line that could not be parsed as a key/value pair.
+    /// Kept verbatim, including its original leading whitespace.
+    Comment(String),
+    /// A line that was parsed as `key=value`; the current value is looked up in `store`
+    /// when the line is written back out.
+    KeyValue(String),
+}
+
+/// Parse a single `KEY=value` line, stripping a leading `export ` from the key.
+///
+/// `raw` holds every physical line of the document; `start` is the line the record begins on.
+/// When the value opens an unterminated quote, subsequent lines are folded in until the
+/// matching close quote is found, so a PEM block or multi-line JSON value parses as one record.
+/// Returns the key, the unescaped value, and the number of lines consumed. If the opening quote
+/// is never closed before the document ends (a missing closing quote, most often a typo), `None`
+/// is returned rather than folding in every remaining line of the file; the caller then falls
+/// back to treating just the opening line as an unparsed record, so later lines keep parsing.
+fn parse_key_value(raw: &[&str], start: usize) -> Option<(String, String, usize)> {
+    let first = raw[start].trim();
+    let first = first.strip_prefix("export ").map_or(first, str::trim_start);
+
+    let pos = first.find('=')?;
+    let key = first[..pos].to_owned();
+    let rest = &first[pos + 1..];
+
+    let quote = rest.starts_with(['\'', '"']).then(|| rest.as_bytes()[0] as char);
+    let quote = match quote {
+        Some(quote) if !quote_is_closed(rest, quote) => quote,
+        _ => return unescape_preserving_dollar_escape(rest).ok().map(|value| (key, value, 1)),
+    };
+
+    let mut value_text = rest.to_owned();
+    let mut consumed = 1;
+    let mut closed = false;
+    for line in &raw[start + 1..] {
+        value_text.push('\n');
+        value_text.push_str(line);
+        consumed += 1;
+        if quote_is_closed(&value_text, quote) {
+            closed = true;
+            break;
+        }
+    }
+
+    if !closed {
+        return None;
+    }
+
+    unescape_preserving_dollar_escape(&value_text).ok().map(|value| (key, value, consumed))
+}
+
+/// A sentinel substituted for a `\$` escape before handing text to `unescape`, and restored
+/// afterward. `snailquote::unescape` resolves `\$` to a bare `$` while inside a quoted string
+/// (unlike outside one, where it leaves the backslash untouched), which would otherwise discard
+/// the escape that [`expand_value`] relies on to suppress expansion. Routing every value through
+/// this sentinel keeps a quoted `"\$BASE"` behaving the same as an unquoted `\$BASE`.
+const ESCAPED_DOLLAR_SENTINEL: &str = "\u{0}ESCAPED_DOLLAR\u{0}";
+
+/// Like `snailquote::unescape`, but a `\$` escape survives unquoting intact instead of being
+/// collapsed to a bare `$`, so [`expand_value`] can still suppress expansion for it later.
+fn unescape_preserving_dollar_escape(text: &str) -> Result<String, snailquote::UnescapeError> {
+    let guarded = text.replace("\\$", ESCAPED_DOLLAR_SENTINEL);
+    unescape(&guarded).map(|value| value.replace(ESCAPED_DOLLAR_SENTINEL, "\\$"))
+}
+
+/// Whether `s` (which begins with the opening `quote`) contains a matching, unescaped close quote.
+fn quote_is_closed(s: &str, quote: char) -> bool {
+    let mut escaped = false;
+    for c in s.chars().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return true;
+        }
+    }
+    false
+}
+
+/// Parse the full contents of an environment file into a lookup table plus the ordered layout
+/// (comments, blank lines, and key/value lines) needed to round-trip it back to a file.
+fn parse_document(data: &[u8]) -> io::Result<(BTreeMap<String, String>, Vec<Line>)> {
+    let mut store = BTreeMap::new();
+    let mut lines = Vec::new();
+
+    let text = str::from_utf8(data).map_err(|why| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("environment data is not valid UTF-8: {}", why),
+    ))?;
+    let raw: Vec<&str> = text.split('\n').collect();
+
+    let mut i = 0;
+    while i < raw.len() {
+        let trimmed = raw[i].trim();
+
+        if trimmed.is_empty() {
+            lines.push(Line::Blank);
+            i += 1;
+            continue;
+        }
+
+        if trimmed.starts_with('#') {
+            lines.push(Line::Comment(raw[i].to_owned()));
+            i += 1;
+            continue;
+        }
+
+        match parse_key_value(&raw, i) {
+            Some((key, value, consumed)) => {
+                lines.push(Line::KeyValue(key.clone()));
+                store.insert(key, value);
+                i += consumed;
+            }
+            None => {
+                lines.push(Line::Comment(raw[i].to_owned()));
+                i += 1;
+            }
+        }
+    }
+
+    // `text.split('\n')` yields a trailing empty entry when the file ends in a newline; drop
+    // the blank line it produces so `write` does not grow the file by one line on every save.
+    if data.last() == Some(&b'\n') {
+        lines.pop();
+    }
+
+    Ok((store, lines))
+}
+
+/// Scan `value` left-to-right, substituting `$VAR` and `${VAR}` references as they are found.
+///
+/// `resolving` holds the stack of keys currently being expanded, so that a key referencing
+/// itself (directly or transitively) can be reported as a cycle instead of recursing forever.
+fn expand_value(
+    value: &str,
+    store: &BTreeMap<String, String>,
+    resolving: &mut Vec<String>,
+) -> io::Result<String> {
+    let chars: Vec<char> = value.chars().collect();
+    let mut output = String::with_capacity(value.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == '\\' && i + 1 < chars.len() && chars[i + 1] == '$' {
+            output.push('$');
+            i += 2;
+            continue;
+        }
+
+        if c != '$' {
+            output.push(c);
+            i += 1;
+            continue;
+        }
+
+        if i + 1 < chars.len() && chars[i + 1] == '{' {
+            let close = match chars[i + 2..].iter().position(|&x| x == '}') {
+                Some(pos) => i + 2 + pos,
+                None => {
+                    output.push(c);
+                    i += 1;
+                    continue;
+                }
+            };
+            let inner: String = chars[i + 2..close].iter().collect();
+            output.push_str(&expand_braced(&inner, store, resolving)?);
+            i = close + 1;
+        } else {
+            let start = i + 1;
+            let mut end = start;
+            while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+                end += 1;
+            }
+            if end == start {
+                output.push(c);
+                i += 1;
+                continue;
+            }
+            let name: String = chars[start..end].iter().collect();
+            output.push_str(&lookup(&name, store, resolving)?);
+            i = end;
+        }
+    }
+
+    Ok(output)
+}
+
+/// Expand the contents of a `${...}` reference, handling the `:-` and `:+` modifiers.
+fn expand_braced(
+    inner: &str,
+    store: &BTreeMap<String, String>,
+    resolving: &mut Vec<String>,
+) -> io::Result<String> {
+    if let Some(pos) = inner.find(":-") {
+        let (name, default) = (&inner[..pos], &inner[pos + 2..]);
+        let resolved = lookup(name, store, resolving)?;
+        if resolved.is_empty() {
+            expand_value(default, store, resolving)
+        } else {
+            Ok(resolved)
+        }
+    } else if let Some(pos) = inner.find(":+") {
+        let (name, alt) = (&inner[..pos], &inner[pos + 2..]);
+        let resolved = lookup(name, store, resolving)?;
+        if resolved.is_empty() {
+            Ok(String::new())
+        } else {
+            expand_value(alt, store, resolving)
+        }
+    } else {
+        lookup(inner, store, resolving)
+    }
+}
+
+/// Resolve a single variable name against the store, falling back to the process environment.
+fn lookup(
+    name: &str,
+    store: &BTreeMap<String, String>,
+    resolving: &mut Vec<String>,
+) -> io::Result<String> {
+    let value = match store.get(name) {
+        Some(value) => value,
+        None => return Ok(env::var(name).unwrap_or_default()),
+    };
+
+    if resolving.contains(&name.to_owned()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("cycle detected while expanding variable {:?}", name),
+        ));
+    }
+
+    resolving.push(name.to_owned());
+    let expanded = expand_value(value, store, resolving)?;
+    resolving.pop();
+    Ok(expanded)
 }
 
 impl EnvFile {
     /// Open and parse an environment file.
     pub fn new<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
         let path = path.into();
-        let data = read(&path)?;
-        let mut store = BTreeMap::new();
-
-        let values = data.split(|&x| x == b'\n').flat_map(parse_line);
+        let mut envfile = Self::from_reader(open(&path)?)?;
+        envfile.path = Some(path);
+        Ok(envfile)
+    }
 
-        for (key, value) in values {
-            store.insert(key, value);
-        }
+    /// Parse environment data from a string, without reading it from a file.
+    pub fn parse_str(data: &str) -> io::Result<Self> {
+        let (store, lines) = parse_document(data.as_bytes())?;
+        Ok(EnvFile { path: None, store, removed: BTreeSet::new(), lines })
+    }
 
-        Ok(EnvFile { path, store })
+    /// Parse environment data from any reader; the result has no backing `path`.
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let (store, lines) = parse_document(&data)?;
+        Ok(EnvFile { path: None, store, removed: BTreeSet::new(), lines })
     }
 
     /// Update or insert a key into the map.
     pub fn update(&mut self, key: &str, value: &str) {
+        self.removed.remove(key);
         self.store.insert(key.into(), value.into());
     }
 
+    /// Record that a key should be unset, so that [`apply_to_command`](EnvFile::apply_to_command)
+    /// removes it from the environment it is applied to, rather than merely omitting it.
+    pub fn remove(&mut self, key: &str) {
+        self.store.remove(key);
+        self.removed.insert(key.into());
+    }
+
     /// Fetch a key from the map.
     pub fn get(&self, key: &str) -> Option<&str> {
         self.store.get(key).as_ref().map(|x| x.as_str())
     }
 
-    /// Write the map back to the original file.
+    /// Apply the file's contents to `cmd` as a patch over its inherited environment.
+    ///
+    /// Rather than clearing the command's environment and rebuilding it from scratch, this
+    /// replays only the mutations the file represents: each stored key is set with
+    /// `Command::env`, and each key recorded via [`remove`](EnvFile::remove) is unset with
+    /// `Command::env_remove`. Everything else is left untouched, so the child still inherits
+    /// the parent's environment.
+    pub fn apply_to_command(&self, cmd: &mut Command) {
+        for key in &self.removed {
+            cmd.env_remove(key);
+        }
+
+        for (key, value) in &self.store {
+            cmd.env(key, value);
+        }
+    }
+
+    /// Open and parse an environment file, then [`expand`](EnvFile::expand) its values.
+    pub fn new_expanded<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let mut envfile = Self::new(path)?;
+        envfile.expand()?;
+        Ok(envfile)
+    }
+
+    /// Resolve `$VAR` and `${VAR}` references found within each value.
+    ///
+    /// References are resolved against other keys in the store, falling back to the live
+    /// process environment via `std::env::var` when a key is not present. The
+    /// `${VAR:-default}` and `${VAR:+alt}` modifiers are supported, and a backslash (`\$`)
+    /// suppresses expansion of the `$` that follows it. Unknown variables with no default
+    /// expand to an empty string, matching dotenv conventions. A direct or transitive
+    /// self-reference is reported as an `io::Error`.
+    ///
+    /// Expansions are computed from the original, unexpanded `store` into a fresh map, so a
+    /// value produced by expansion (e.g. a literal `$` from a `\$` escape) is never re-scanned
+    /// as a reference by a key that comes after it. Keys are visited in file order (falling
+    /// back to `store` order for keys with no corresponding line, such as those added via
+    /// [`update`](EnvFile::update)), matching the order this crate otherwise preserves.
+    pub fn expand(&mut self) -> io::Result<()> {
+        let original = self.store.clone();
+        let mut expanded = BTreeMap::new();
+
+        let mut order: Vec<&String> = self.lines.iter().filter_map(|line| match line {
+            Line::KeyValue(key) => Some(key),
+            _ => None,
+        }).collect();
+        for key in original.keys() {
+            if !order.contains(&key) {
+                order.push(key);
+            }
+        }
+
+        for key in order {
+            if expanded.contains_key(key) {
+                continue;
+            }
+            if let Some(value) = original.get(key) {
+                let mut resolving = vec![key.clone()];
+                let resolved = expand_value(value, &original, &mut resolving)?;
+                expanded.insert(key.clone(), resolved);
+            }
+        }
+
+        self.store = expanded;
+        Ok(())
+    }
+
+    /// Serialize the file's current contents to a `String`.
     ///
     /// # Notes
-    /// The keys are written in ascending order.
-    pub fn write(&mut self) -> io::Result<()> {
-        let mut buffer = Vec::with_capacity(1024);
+    /// Comments, blank lines, and key order from the original file are preserved. Keys whose
+    /// values changed are updated in place; keys that were never part of the original layout
+    /// (added via [`update`](EnvFile::update)) are appended at the end, in ascending order.
+    #[allow(clippy::inherent_to_string)]
+    pub fn to_string(&self) -> String {
+        let mut buffer = String::with_capacity(1024);
+        let mut seen = BTreeSet::new();
+
+        // A key may own more than one `Line::KeyValue` entry if the original file assigned it
+        // twice (last-value-wins, per dotenv convention); only its last occurrence is
+        // rewritten with the current value, so the duplicate collapses to a single line.
+        let mut last_occurrence: BTreeMap<&str, usize> = BTreeMap::new();
+        for (index, line) in self.lines.iter().enumerate() {
+            if let Line::KeyValue(key) = line {
+                last_occurrence.insert(key.as_str(), index);
+            }
+        }
+
+        for (index, line) in self.lines.iter().enumerate() {
+            match line {
+                Line::Blank => buffer.push('\n'),
+                Line::Comment(text) => {
+                    buffer.push_str(text);
+                    buffer.push('\n');
+                }
+                Line::KeyValue(key) => {
+                    if last_occurrence.get(key.as_str()) != Some(&index) {
+                        continue;
+                    }
+                    if let Some(value) = self.store.get(key) {
+                        seen.insert(key.clone());
+                        buffer.push_str(key);
+                        buffer.push('=');
+                        // The value may contain space and need to be quoted
+                        buffer.push_str(&escape(value.as_str()));
+                        buffer.push('\n');
+                    }
+                }
+            }
+        }
+
         for (key, value) in &self.store {
-            buffer.extend_from_slice(key.as_bytes());
-            buffer.push(b'=');
-            // The value may contain space and need to be quoted
-            let v = escape(value.as_str()).into_owned();
-            buffer.extend_from_slice(v.as_bytes());
-            buffer.push(b'\n');
+            if seen.contains(key) {
+                continue;
+            }
+            buffer.push_str(key);
+            buffer.push('=');
+            buffer.push_str(&escape(value.as_str()));
+            buffer.push('\n');
         }
 
-        write(&self.path, &buffer)
+        buffer
+    }
+
+    /// Write the map back to the original file.
+    ///
+    /// Delegates to [`to_string`](EnvFile::to_string); see its notes on what is preserved.
+    pub fn write(&mut self) -> io::Result<()> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            io::Error::other("this EnvFile has no backing path to write to")
+        })?;
+
+        write(path, self.to_string())
     }
 }
 
@@ -120,6 +487,7 @@ fn create<P: AsRef<Path>>(path: P) -> io::Result<File> {
     ))
 }
 
+#[cfg(test)]
 fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     open(path).and_then(|mut file| {
         let mut buffer = Vec::with_capacity(file.metadata().ok().map_or(0, |x| x.len()) as usize);
@@ -155,7 +523,9 @@ ROOT_UUID=2ef950c2-5ce6-4ae0-9fb9-a8c7468fa82c
 SINGLE_QUOTED_STRING='This is a single-quoted string'
 "#;
 
-    const SAMPLE_CLEANED: &str = r#"DOUBLE_QUOTED_STRING="This is a 'double-quoted' string"
+    // `write()` preserves comments, blank lines, and key order from SAMPLE; only the stray
+    // leading space before `LANG` is lost, since key/value lines are always re-serialized.
+    const SAMPLE_PRESERVED: &str = r#"DOUBLE_QUOTED_STRING="This is a 'double-quoted' string"
 EFI_UUID=DFFD-D047
 HOSTNAME=pop-testing
 KBD_LAYOUT=us
@@ -163,6 +533,9 @@ KBD_MODEL=
 KBD_VARIANT=
 LANG=en_US.UTF-8
 OEM_MODE=0
+# Intentional blank line
+
+# Should ignore = operator in comment
 RECOVERY_UUID=PARTUUID=asdfasd7asdf7sad-asdfa
 ROOT_UUID=2ef950c2-5ce6-4ae0-9fb9-a8c7468fa82c
 SINGLE_QUOTED_STRING='This is a single-quoted string'
@@ -210,6 +583,171 @@ SINGLE_QUOTED_STRING='This is a single-quoted string'
         env.write().unwrap();
         let copy: &[u8] = &read(path).unwrap();
 
-        assert_eq!(copy, SAMPLE_CLEANED.as_bytes(), "Expected '{}' == '{}'", String::from_utf8_lossy(copy), SAMPLE_CLEANED);
+        assert_eq!(copy, SAMPLE_PRESERVED.as_bytes(), "Expected '{}' == '{}'", String::from_utf8_lossy(copy), SAMPLE_PRESERVED);
+    }
+
+    #[test]
+    fn env_file_write_appends_new_keys() {
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        {
+            let mut file = create(path).unwrap();
+            file.write_all(b"HOSTNAME=pop-testing\n").unwrap();
+        }
+
+        let mut env = EnvFile::new(path).unwrap();
+        env.update("HOSTNAME", "new-hostname");
+        env.update("ID", "example");
+        env.write().unwrap();
+
+        let copy = String::from_utf8(read(path).unwrap()).unwrap();
+        assert_eq!(copy, "HOSTNAME=new-hostname\nID=example\n");
+    }
+
+    #[test]
+    fn env_file_to_string_collapses_duplicate_keys() {
+        let mut env = EnvFile::parse_str("A=1\nB=2\nA=3\n").unwrap();
+        env.update("A", "99");
+
+        assert_eq!(env.to_string(), "B=2\nA=99\n");
+    }
+
+    #[test]
+    fn env_file_parse_str() {
+        let env = EnvFile::parse_str("HOSTNAME=pop-testing\n# a comment\nLANG=en_US.UTF-8\n").unwrap();
+
+        assert!(env.path.is_none());
+        assert_eq!(env.get("HOSTNAME"), Some("pop-testing"));
+        assert_eq!(env.get("LANG"), Some("en_US.UTF-8"));
+        assert_eq!(env.to_string(), "HOSTNAME=pop-testing\n# a comment\nLANG=en_US.UTF-8\n");
+    }
+
+    #[test]
+    fn env_file_from_reader() {
+        let data: &[u8] = b"HOSTNAME=pop-testing\n";
+        let env = EnvFile::from_reader(data).unwrap();
+
+        assert!(env.path.is_none());
+        assert_eq!(env.get("HOSTNAME"), Some("pop-testing"));
+    }
+
+    #[test]
+    fn env_file_write_without_path_fails() {
+        let mut env = EnvFile::parse_str("HOSTNAME=pop-testing\n").unwrap();
+        assert!(env.write().is_err());
+    }
+
+    #[test]
+    fn env_file_export_prefix() {
+        let env = EnvFile::parse_str("export HOSTNAME=pop-testing\nexport  LANG=en_US.UTF-8\n").unwrap();
+
+        assert_eq!(env.get("HOSTNAME"), Some("pop-testing"));
+        assert_eq!(env.get("LANG"), Some("en_US.UTF-8"));
+    }
+
+    #[test]
+    fn env_file_invalid_utf8_errors() {
+        let data: &[u8] = b"GOOD=1\n\xFF\xFE\nOTHER=2\n";
+        assert!(EnvFile::from_reader(data).is_err());
+    }
+
+    #[test]
+    fn env_file_multiline_quoted_value() {
+        let sample = "CERT=\"-----BEGIN-----\nline one\nline two\n-----END-----\"\nAFTER=1\n";
+        let env = EnvFile::parse_str(sample).unwrap();
+
+        assert_eq!(env.get("CERT"), Some("-----BEGIN-----\nline one\nline two\n-----END-----"));
+        assert_eq!(env.get("AFTER"), Some("1"));
+    }
+
+    #[test]
+    fn env_file_unterminated_quote_does_not_swallow_rest_of_file() {
+        let env = EnvFile::parse_str("A=\"unterminated\nB=2\nC=3\n").unwrap();
+
+        assert_eq!(env.get("B"), Some("2"));
+        assert_eq!(env.get("C"), Some("3"));
+    }
+
+    #[test]
+    fn env_file_expand() {
+        const SAMPLE: &str = r#"BASE=/opt
+PATH_EXT=${BASE}:/opt/bin
+ESCAPED=\${BASE}
+WITH_DEFAULT=${MISSING:-fallback}
+WITH_ALT=${BASE:+present}
+UNKNOWN=${MISSING}
+"#;
+
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        {
+            let mut file = create(path).unwrap();
+            file.write_all(SAMPLE.as_bytes()).unwrap();
+        }
+
+        let mut env = EnvFile::new(path).unwrap();
+        env.expand().unwrap();
+
+        assert_eq!(env.get("PATH_EXT"), Some("/opt:/opt/bin"));
+        assert_eq!(env.get("ESCAPED"), Some("${BASE}"));
+        assert_eq!(env.get("WITH_DEFAULT"), Some("fallback"));
+        assert_eq!(env.get("WITH_ALT"), Some("present"));
+        assert_eq!(env.get("UNKNOWN"), Some(""));
+    }
+
+    #[test]
+    fn env_file_expand_quoted_escape() {
+        let mut env = EnvFile::parse_str("BASE=/opt\nESCAPED=\"\\$BASE\"\n").unwrap();
+        env.expand().unwrap();
+
+        assert_eq!(env.get("ESCAPED"), Some("$BASE"));
+    }
+
+    #[test]
+    fn env_file_expand_does_not_rescan_expanded_values() {
+        let mut env = EnvFile::parse_str("A=\\$100\nB=${A}\n").unwrap();
+        env.expand().unwrap();
+
+        assert_eq!(env.get("A"), Some("$100"));
+        assert_eq!(env.get("B"), Some("$100"));
+    }
+
+    #[test]
+    fn env_file_apply_to_command() {
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        {
+            let mut file = create(path).unwrap();
+            file.write_all(b"HOSTNAME=pop-testing\n").unwrap();
+        }
+
+        let mut env = EnvFile::new(path).unwrap();
+        env.remove("LANG");
+
+        let mut cmd = std::process::Command::new("env");
+        env.apply_to_command(&mut cmd);
+
+        let envs: BTreeMap<_, _> = cmd.get_envs().collect();
+        assert_eq!(envs.get(std::ffi::OsStr::new("HOSTNAME")), Some(&Some(std::ffi::OsStr::new("pop-testing"))));
+        assert_eq!(envs.get(std::ffi::OsStr::new("LANG")), Some(&None));
+    }
+
+    #[test]
+    fn env_file_expand_cycle() {
+        const SAMPLE: &str = "A=${B}\nB=${A}\n";
+
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        {
+            let mut file = create(path).unwrap();
+            file.write_all(SAMPLE.as_bytes()).unwrap();
+        }
+
+        let mut env = EnvFile::new(path).unwrap();
+        assert!(env.expand().is_err());
     }
 }